@@ -0,0 +1,637 @@
+//! Coalesce many requests into a single call to the inner service.
+//!
+//! Like `buffer`, `Batch` spawns a dedicated worker task that owns the inner
+//! service and pulls requests off an `mpsc` channel, which is what lets
+//! `Batch` be `Clone` even when the inner service is not. Unlike `buffer`,
+//! the worker does not dispatch one request at a time: it accumulates
+//! requests into a `Vec` and flushes them to the inner service in a single
+//! call, once the batch is full or a latency deadline passes, whichever
+//! comes first. This amortizes per-call overhead across many requests, which
+//! is a large win for things like crypto verification or DB round trips.
+
+use futures::future::Executor;
+use futures::sync::mpsc;
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll, Stream};
+use tokio_timer::Delay;
+use tower_service::Service;
+use DirectService;
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{error, fmt};
+
+/// The response type yielded for a single request in a batch.
+type BatchItem<T, Request> = <<T as Service<Vec<Request>>>::Response as IntoIterator>::Item;
+
+/// Adds a batching layer in front of an inner service.
+///
+/// See module level documentation for more details.
+pub struct Batch<T, Request>
+where
+    T: DirectService<Vec<Request>>,
+    T::Response: IntoIterator,
+{
+    tx: mpsc::Sender<Message<T, Request>>,
+    state: Arc<State<T::Error>>,
+    /// Set when `poll_ready` last returned `Ready`, and cleared by `call`.
+    /// Lets `call` enforce that it is never invoked without a preceding
+    /// successful `poll_ready`.
+    ready: bool,
+}
+
+/// Future eventually completed with the response to the original request.
+pub struct ResponseFuture<T, Request>
+where
+    T: DirectService<Vec<Request>>,
+    T::Response: IntoIterator,
+{
+    state: ResponseState<BatchItem<T, Request>, T::Error>,
+    shared: Arc<State<T::Error>>,
+}
+
+enum ResponseState<T, E> {
+    Failed(Arc<ServiceError<E>>),
+    Full,
+    Rx(oneshot::Receiver<Result<T, Arc<ServiceError<E>>>>),
+}
+
+/// Errors produced by `Batch`.
+#[derive(Debug)]
+pub enum Error<T> {
+    /// The `Service` call errored. `Batch` reports a failed batched call as
+    /// `Closed(ServiceError::Call(..))` and does not produce this variant.
+    Inner(T),
+    /// The underlying `Service` failed, carrying the error that caused the
+    /// batch worker to close, or `ServiceError::BatchSizeMismatch` for a
+    /// request whose batch came back short, which leaves the worker open.
+    Closed(Arc<ServiceError<T>>),
+    /// The batch is at capacity. This is not fatal: wait for `poll_ready` to
+    /// report `Ready` again and retry the call.
+    Full,
+}
+
+/// Records which `Worker`-driven operation observed the inner service fail.
+#[derive(Debug)]
+pub enum ServiceError<T> {
+    /// `poll_ready` returned an error.
+    PollReady(T),
+    /// The batched call itself returned an error.
+    Call(T),
+    /// `poll_outstanding` returned an error while driving in-flight requests.
+    PollOutstanding(T),
+    /// `poll_close` returned an error while shutting the service down.
+    PollClose(T),
+    /// The worker task terminated (e.g. it panicked, or the executor it was
+    /// spawned on was shut down) without getting a chance to record why.
+    Terminated,
+    /// The inner service's batched future resolved with fewer responses
+    /// than requests in the batch, so not every caller could be paired with
+    /// a response. This is specific to the batch that was in flight and
+    /// doesn't imply the worker or any other caller is unhealthy.
+    BatchSizeMismatch,
+}
+
+/// Task that handles accumulating and flushing batches. This type should not
+/// be used directly, instead `Batch` requires an `Executor` that can accept
+/// this task.
+pub struct Worker<T, Request>
+where
+    T: DirectService<Vec<Request>>,
+    T::Response: IntoIterator,
+{
+    rx: mpsc::Receiver<Message<T, Request>>,
+    service: T,
+    state: Arc<State<T::Error>>,
+    finish: bool,
+
+    max_items: usize,
+    max_latency: Duration,
+    delay: Option<Delay>,
+
+    /// Requests accumulated for the next batch, along with the sender each
+    /// eventual response should be routed to.
+    pending: Vec<Request>,
+    waiting: Vec<Waiting<T, Request>>,
+
+    /// The batch currently in flight, if any, and the senders waiting on it.
+    flushing: Option<(T::Future, Vec<Waiting<T, Request>>)>,
+}
+
+/// Error produced when spawning the worker fails
+#[derive(Debug)]
+pub struct SpawnError<T> {
+    inner: T,
+}
+
+type Waiting<T, Request> = oneshot::Sender<Result<BatchItem<T, Request>, Arc<ServiceError<<T as Service<Vec<Request>>>::Error>>>>;
+
+/// Message sent over the batch channel
+struct Message<T, Request>
+where
+    T: DirectService<Vec<Request>>,
+    T::Response: IntoIterator,
+{
+    request: Request,
+    tx: Waiting<T, Request>,
+}
+
+/// State shared between `Batch` and `Worker`.
+struct State<E> {
+    error: Mutex<Option<Arc<ServiceError<E>>>>,
+}
+
+impl<E> State<E> {
+    fn new() -> Self {
+        State {
+            error: Mutex::new(None),
+        }
+    }
+
+    fn get_error(&self) -> Option<Arc<ServiceError<E>>> {
+        self.error.lock().unwrap().clone()
+    }
+
+    fn set_error(&self, error: Arc<ServiceError<E>>) {
+        *self.error.lock().unwrap() = Some(error);
+    }
+
+    /// Like `get_error`, but if no error has been recorded yet, assumes the
+    /// worker terminated without getting a chance to record one (e.g. it
+    /// panicked, or its executor was shut down) and records that instead.
+    fn get_error_or_terminated(&self) -> Arc<ServiceError<E>> {
+        let mut guard = self.error.lock().unwrap();
+        if let Some(error) = guard.clone() {
+            return error;
+        }
+        let error = Arc::new(ServiceError::Terminated);
+        *guard = Some(error.clone());
+        error
+    }
+}
+
+impl<T, Request> Batch<T, Request>
+where
+    T: DirectService<Vec<Request>>,
+    T::Response: IntoIterator,
+{
+    /// Creates a new `Batch` wrapping `service`.
+    ///
+    /// `executor` is used to spawn a new `Worker` task that is dedicated to
+    /// accumulating requests and dispatching them to the inner service in
+    /// bulk.
+    ///
+    /// At most `max_items` requests are collected into a single batch before
+    /// it is flushed. If fewer than `max_items` requests arrive, the batch
+    /// is instead flushed `max_latency` after the first request in it
+    /// arrived, whichever happens first.
+    pub fn new<E>(
+        service: T,
+        max_items: usize,
+        max_latency: Duration,
+        executor: &E,
+    ) -> Result<Self, SpawnError<T>>
+    where
+        E: Executor<Worker<T, Request>>,
+        T::Error: Send + Sync,
+    {
+        let (tx, rx) = mpsc::channel(max_items);
+
+        let state = Arc::new(State::new());
+
+        let worker = Worker {
+            rx,
+            service,
+            state: state.clone(),
+            finish: false,
+            max_items,
+            max_latency,
+            delay: None,
+            pending: Vec::new(),
+            waiting: Vec::new(),
+            flushing: None,
+        };
+
+        match executor.execute(worker) {
+            Ok(()) => Ok(Batch {
+                tx,
+                state,
+                ready: false,
+            }),
+            Err(err) => Err(SpawnError {
+                inner: err.into_future().service,
+            }),
+        }
+    }
+}
+
+impl<T, Request> Service<Request> for Batch<T, Request>
+where
+    T: DirectService<Vec<Request>>,
+    T::Response: IntoIterator,
+    T::Error: Send + Sync,
+{
+    type Response = BatchItem<T, Request>;
+    type Error = Error<T::Error>;
+    type Future = ResponseFuture<T, Request>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.ready = false;
+
+        if let Some(error) = self.state.get_error() {
+            return Err(Error::Closed(error));
+        }
+
+        match self.tx.poll_ready() {
+            Ok(Async::Ready(())) => {
+                self.ready = true;
+                Ok(Async::Ready(()))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(Error::Closed(self.state.get_error_or_terminated())),
+        }
+    }
+
+    /// # Panics
+    ///
+    /// Panics if called without a preceding call to `poll_ready` that
+    /// returned `Async::Ready`, per the `Service` contract that `call` may
+    /// assume readiness was already checked.
+    fn call(&mut self, request: Request) -> Self::Future {
+        assert!(
+            self.ready,
+            "called Batch::call before Batch::poll_ready reported Ready"
+        );
+        self.ready = false;
+
+        let (tx, rx) = oneshot::channel();
+
+        match self.tx.try_send(Message { request, tx }) {
+            Ok(()) => ResponseFuture {
+                state: ResponseState::Rx(rx),
+                shared: self.state.clone(),
+            },
+            // We raced another clone of this batch for the last slot; tell
+            // the caller to try again once `poll_ready` is `Ready`, rather
+            // than tearing the whole batch down.
+            Err(ref e) if e.is_full() => ResponseFuture {
+                state: ResponseState::Full,
+                shared: self.state.clone(),
+            },
+            Err(_) => ResponseFuture {
+                state: ResponseState::Failed(self.state.get_error_or_terminated()),
+                shared: self.state.clone(),
+            },
+        }
+    }
+}
+
+impl<T, Request> Clone for Batch<T, Request>
+where
+    T: DirectService<Vec<Request>>,
+    T::Response: IntoIterator,
+{
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            state: self.state.clone(),
+            // The clone hasn't had `poll_ready` called on it yet.
+            ready: false,
+        }
+    }
+}
+
+// ===== impl ResponseFuture =====
+
+impl<T, Request> Future for ResponseFuture<T, Request>
+where
+    T: DirectService<Vec<Request>>,
+    T::Response: IntoIterator,
+    T::Error: Send + Sync,
+{
+    type Item = BatchItem<T, Request>;
+    type Error = Error<T::Error>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.state {
+            ResponseState::Failed(ref error) => Err(Error::Closed(error.clone())),
+            ResponseState::Full => Err(Error::Full),
+            ResponseState::Rx(ref mut rx) => match rx.poll() {
+                Ok(Async::Ready(Ok(item))) => Ok(Async::Ready(item)),
+                Ok(Async::Ready(Err(error))) => Err(Error::Closed(error)),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                Err(_) => {
+                    // The worker dropped our sender without replying, most
+                    // likely because it terminated unexpectedly.
+                    Err(Error::Closed(self.shared.get_error_or_terminated()))
+                }
+            },
+        }
+    }
+}
+
+// ===== impl Worker =====
+
+impl<T, Request> Worker<T, Request>
+where
+    T: DirectService<Vec<Request>>,
+    T::Response: IntoIterator,
+{
+    /// Pull any newly arrived messages into the pending batch, starting the
+    /// latency timer the moment the batch becomes non-empty. Returns
+    /// `Ready` once the batch is full or the channel has closed, and
+    /// `NotReady` once the channel has no more messages for now.
+    fn poll_fill_batch(&mut self) -> Async<()> {
+        if self.finish || (!self.pending.is_empty() && self.pending.len() >= self.max_items) {
+            return Async::Ready(());
+        }
+
+        loop {
+            match self.rx.poll() {
+                Ok(Async::Ready(Some(msg))) => {
+                    if self.pending.is_empty() {
+                        self.delay = Some(Delay::new(Instant::now() + self.max_latency));
+                    }
+                    self.pending.push(msg.request);
+                    self.waiting.push(msg.tx);
+                    if self.pending.len() >= self.max_items {
+                        return Async::Ready(());
+                    }
+                }
+                Ok(Async::Ready(None)) => {
+                    self.finish = true;
+                    return Async::Ready(());
+                }
+                Ok(Async::NotReady) => return Async::NotReady,
+                // The channel's error type is `()` and it never actually errors.
+                Err(()) => return Async::NotReady,
+            }
+        }
+    }
+
+    /// Whether the accumulated batch should be flushed now: it's full, the
+    /// latency deadline has passed, or we're shutting down with a partial
+    /// batch left to send.
+    fn batch_ready(&mut self) -> bool {
+        if self.pending.is_empty() {
+            return false;
+        }
+        if self.pending.len() >= self.max_items || self.finish {
+            return true;
+        }
+        match self.delay {
+            Some(ref mut delay) => match delay.poll() {
+                Ok(Async::Ready(())) => true,
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    fn failed(&mut self, error: ServiceError<T::Error>)
+    where
+        T::Error: Send + Sync,
+    {
+        let error = Arc::new(error);
+        self.fail_all(error);
+    }
+
+    fn fail_all(&mut self, error: Arc<ServiceError<T::Error>>)
+    where
+        T::Error: Send + Sync,
+    {
+        self.state.set_error(error.clone());
+
+        for tx in self.waiting.drain(..) {
+            let _ = tx.send(Err(error.clone()));
+        }
+        if let Some((_, waiting)) = self.flushing.take() {
+            for tx in waiting {
+                let _ = tx.send(Err(error.clone()));
+            }
+        }
+        while let Ok(Async::Ready(Some(msg))) = self.rx.poll() {
+            let _ = msg.tx.send(Err(error.clone()));
+        }
+    }
+}
+
+impl<T, Request> Future for Worker<T, Request>
+where
+    T: DirectService<Vec<Request>>,
+    T::Response: IntoIterator,
+    T::Error: Send + Sync,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            // Drive a batch we've already dispatched to completion before
+            // flushing the next one.
+            let mut flush_in_progress = false;
+            if let Some((mut fut, waiting)) = self.flushing.take() {
+                match fut.poll() {
+                    Ok(Async::Ready(responses)) => {
+                        // Pair up responses with the callers waiting on
+                        // them one at a time, rather than `zip`, which
+                        // would silently drop any senders beyond
+                        // `responses.len()`. A dropped sender looks to its
+                        // `ResponseFuture` like the worker terminated,
+                        // which would incorrectly poison `state` for every
+                        // other, unrelated caller even though the worker
+                        // and inner service are both fine.
+                        let mut responses = responses.into_iter();
+                        for tx in waiting {
+                            match responses.next() {
+                                Some(response) => {
+                                    let _ = tx.send(Ok(response));
+                                }
+                                None => {
+                                    debug_assert!(
+                                        false,
+                                        "inner service returned fewer responses than requests in the batch"
+                                    );
+                                    let _ = tx.send(Err(Arc::new(ServiceError::BatchSizeMismatch)));
+                                }
+                            }
+                        }
+                    }
+                    Ok(Async::NotReady) => {
+                        self.flushing = Some((fut, waiting));
+                        flush_in_progress = true;
+                    }
+                    Err(e) => {
+                        let error = Arc::new(ServiceError::Call(e));
+                        for tx in waiting {
+                            let _ = tx.send(Err(error.clone()));
+                        }
+                        self.fail_all(error);
+                        return Ok(().into());
+                    }
+                }
+            }
+
+            if flush_in_progress {
+                // We can't dispatch another batch until this one completes,
+                // but we should still accept new requests (and start their
+                // latency deadline) in the meantime, rather than leaving
+                // them unseen in `rx` until the flush happens to finish.
+                let _ = self.poll_fill_batch();
+                if let Err(e) = self.service.poll_outstanding() {
+                    self.failed(ServiceError::PollOutstanding(e));
+                    return Ok(().into());
+                }
+                return Ok(Async::NotReady);
+            }
+
+            // Even if no more messages are waiting, a partial batch may be
+            // due to flush, and `batch_ready` must poll the latency deadline
+            // so this task is woken when it passes.
+            let _ = self.poll_fill_batch();
+
+            if !self.batch_ready() {
+                if self.finish {
+                    match self.service.poll_close() {
+                        Ok(Async::Ready(())) => return Ok(().into()),
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(e) => {
+                            self.failed(ServiceError::PollClose(e));
+                            return Ok(().into());
+                        }
+                    }
+                }
+                if let Err(e) = self.service.poll_outstanding() {
+                    self.failed(ServiceError::PollOutstanding(e));
+                    return Ok(().into());
+                }
+                return Ok(Async::NotReady);
+            }
+
+            match self.service.poll_ready() {
+                Ok(Async::Ready(())) => {
+                    let batch = mem::replace(&mut self.pending, Vec::new());
+                    let waiting = mem::replace(&mut self.waiting, Vec::new());
+                    self.delay = None;
+                    let fut = self.service.call(batch);
+                    self.flushing = Some((fut, waiting));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    self.failed(ServiceError::PollReady(e));
+                    return Ok(().into());
+                }
+            }
+        }
+    }
+}
+
+// ===== impl Error =====
+
+impl<T> fmt::Display for Error<T>
+where
+    T: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Inner(ref why) => fmt::Display::fmt(why, f),
+            Error::Closed(ref why) => write!(f, "batch closed: {}", why),
+            Error::Full => f.pad("batch at capacity; await poll_ready before retrying"),
+        }
+    }
+}
+
+impl<T> error::Error for Error<T>
+where
+    T: error::Error,
+{
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Inner(ref why) => Some(why),
+            Error::Closed(ref why) => Some(why.as_ref()),
+            Error::Full => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            Error::Inner(ref e) => e.description(),
+            Error::Closed(_) => "batch closed",
+            Error::Full => "batch at capacity",
+        }
+    }
+}
+
+// ===== impl ServiceError =====
+
+impl<T> fmt::Display for ServiceError<T>
+where
+    T: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServiceError::PollReady(ref why) => write!(f, "poll_ready failed: {}", why),
+            ServiceError::Call(ref why) => write!(f, "batched call failed: {}", why),
+            ServiceError::PollOutstanding(ref why) => write!(f, "poll_outstanding failed: {}", why),
+            ServiceError::PollClose(ref why) => write!(f, "poll_close failed: {}", why),
+            ServiceError::Terminated => f.pad("worker terminated unexpectedly"),
+            ServiceError::BatchSizeMismatch => {
+                f.pad("inner service returned fewer responses than requests in the batch")
+            }
+        }
+    }
+}
+
+impl<T> error::Error for ServiceError<T>
+where
+    T: error::Error,
+{
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ServiceError::PollReady(ref why) => Some(why),
+            ServiceError::Call(ref why) => Some(why),
+            ServiceError::PollOutstanding(ref why) => Some(why),
+            ServiceError::PollClose(ref why) => Some(why),
+            ServiceError::Terminated => None,
+            ServiceError::BatchSizeMismatch => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            ServiceError::PollReady(_) => "poll_ready failed",
+            ServiceError::Call(_) => "batched call failed",
+            ServiceError::PollOutstanding(_) => "poll_outstanding failed",
+            ServiceError::PollClose(_) => "poll_close failed",
+            ServiceError::Terminated => "worker terminated unexpectedly",
+            ServiceError::BatchSizeMismatch => {
+                "inner service returned fewer responses than requests in the batch"
+            }
+        }
+    }
+}
+
+// ===== impl SpawnError =====
+
+impl<T> fmt::Display for SpawnError<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error spawning batch task: {:?}", self.inner)
+    }
+}
+
+impl<T> error::Error for SpawnError<T>
+where
+    T: error::Error,
+{
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.inner)
+    }
+
+    fn description(&self) -> &str {
+        "error spawning batch task"
+    }
+}
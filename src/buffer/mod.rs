@@ -5,7 +5,9 @@
 //! buffer and a dedicated task, the `Buffer` layer in front of the service can
 //! be `Clone` even if the inner service is not.
 //!
-//! This is a version of `tower-buffer` adapted to use `DirectService`.
+//! This is a version of `tower-buffer` adapted to use `DirectService`. For an
+//! inner service that is an ordinary `tower_service::Service` and drives its
+//! own futures, see `ServiceBuffer` instead.
 
 use futures::future::Executor;
 use futures::sync::mpsc;
@@ -14,9 +16,7 @@ use futures::{Async, Future, Poll, Stream};
 use tower_service::Service;
 use DirectService;
 
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::{error, fmt};
 
 /// Adds a buffer in front of an inner service.
@@ -27,7 +27,11 @@ where
     T: DirectService<Request>,
 {
     tx: mpsc::Sender<Message<T, Request>>,
-    state: Arc<State>,
+    state: Arc<State<T::Error>>,
+    /// Set when `poll_ready` last returned `Ready`, and cleared by `call`.
+    /// Lets `call` enforce that it is never invoked without a preceding
+    /// successful `poll_ready`.
+    ready: bool,
 }
 
 /// Future eventually completed with the response to the original request.
@@ -35,7 +39,8 @@ pub struct ResponseFuture<T, Request>
 where
     T: DirectService<Request>,
 {
-    state: ResponseState<T::Future>,
+    state: ResponseState<T::Future, T::Error>,
+    shared: Arc<State<T::Error>>,
 }
 
 /// Errors produced by `Buffer`.
@@ -43,8 +48,30 @@ where
 pub enum Error<T> {
     /// The `Service` call errored.
     Inner(T),
-    /// The underlying `Service` failed.
-    Closed,
+    /// The underlying `Service` failed, carrying the error that caused the
+    /// buffer to close.
+    Closed(Arc<ServiceError<T>>),
+    /// The buffer is at capacity. This is not fatal: wait for `poll_ready`
+    /// to report `Ready` again and retry the call.
+    Full,
+}
+
+/// Records which `Worker`-driven method observed the inner service fail.
+///
+/// A single instance of this is shared (via `Arc`) with every pending and
+/// future caller once the inner service breaks, so they all see the same
+/// concrete cause instead of an opaque closed error.
+#[derive(Debug)]
+pub enum ServiceError<T> {
+    /// `poll_ready` returned an error.
+    PollReady(T),
+    /// `poll_outstanding` returned an error while driving in-flight requests.
+    PollOutstanding(T),
+    /// `poll_close` returned an error while shutting the service down.
+    PollClose(T),
+    /// The worker task terminated (e.g. it panicked, or the executor it was
+    /// spawned on was shut down) without getting a chance to record why.
+    Terminated,
 }
 
 /// Task that handles processing the buffer. This type should not be used
@@ -57,7 +84,7 @@ where
     rx: mpsc::Receiver<Message<T, Request>>,
     service: T,
     finish: bool,
-    state: Arc<State>,
+    state: Arc<State<T::Error>>,
 }
 
 /// Error produced when spawning the worker fails
@@ -73,17 +100,50 @@ where
     T: DirectService<Request>,
 {
     request: Request,
-    tx: oneshot::Sender<T::Future>,
+    tx: oneshot::Sender<Result<T::Future, Arc<ServiceError<T::Error>>>>,
 }
 
-/// State shared between `Buffer` and `Worker`
-struct State {
-    open: AtomicBool,
+/// State shared between `Buffer` and `Worker`.
+struct State<E> {
+    /// Set once the inner service has failed. Every caller that observes
+    /// `Some` here gets a clone of the same `Arc`, so they all learn the
+    /// same concrete cause.
+    error: Mutex<Option<Arc<ServiceError<E>>>>,
+}
+
+impl<E> State<E> {
+    fn new() -> Self {
+        State {
+            error: Mutex::new(None),
+        }
+    }
+
+    fn get_error(&self) -> Option<Arc<ServiceError<E>>> {
+        self.error.lock().unwrap().clone()
+    }
+
+    fn set_error(&self, error: Arc<ServiceError<E>>) {
+        *self.error.lock().unwrap() = Some(error);
+    }
+
+    /// Like `get_error`, but if no error has been recorded yet, assumes the
+    /// worker terminated without getting a chance to record one (e.g. it
+    /// panicked, or its executor was shut down) and records that instead.
+    fn get_error_or_terminated(&self) -> Arc<ServiceError<E>> {
+        let mut guard = self.error.lock().unwrap();
+        if let Some(error) = guard.clone() {
+            return error;
+        }
+        let error = Arc::new(ServiceError::Terminated);
+        *guard = Some(error.clone());
+        error
+    }
 }
 
-enum ResponseState<T> {
-    Failed,
-    Rx(oneshot::Receiver<T>),
+enum ResponseState<T, E> {
+    Failed(Arc<ServiceError<E>>),
+    Full,
+    Rx(oneshot::Receiver<Result<T, Arc<ServiceError<E>>>>),
     Poll(T),
 }
 
@@ -102,12 +162,11 @@ where
     pub fn new<E>(service: T, bound: usize, executor: &E) -> Result<Self, SpawnError<T>>
     where
         E: Executor<Worker<T, Request>>,
+        T::Error: Send + Sync,
     {
         let (tx, rx) = mpsc::channel(bound);
 
-        let state = Arc::new(State {
-            open: AtomicBool::new(true),
-        });
+        let state = Arc::new(State::new());
 
         let worker = Worker {
             current_message: None,
@@ -117,47 +176,80 @@ where
             state: state.clone(),
         };
 
-        // TODO: handle error
-        executor.execute(worker).ok().unwrap();
-
-        Ok(Buffer { tx, state: state })
+        match executor.execute(worker) {
+            Ok(()) => Ok(Buffer {
+                tx,
+                state,
+                ready: false,
+            }),
+            Err(err) => Err(SpawnError {
+                inner: err.into_future().service,
+            }),
+        }
     }
 }
 
 impl<T, Request> Service<Request> for Buffer<T, Request>
 where
     T: DirectService<Request>,
+    T::Error: Send + Sync,
 {
     type Response = T::Response;
     type Error = Error<T::Error>;
     type Future = ResponseFuture<T, Request>;
 
     fn poll_ready(&mut self) -> Poll<(), Self::Error> {
-        // If the inner service has errored, then we error here.
-        if !self.state.open.load(Ordering::Acquire) {
-            return Err(Error::Closed);
-        } else {
-            self.tx.poll_ready().map_err(|_| Error::Closed)
+        self.ready = false;
+
+        // If the inner service has errored, then we error here, handing back
+        // the concrete cause.
+        if let Some(error) = self.state.get_error() {
+            return Err(Error::Closed(error));
+        }
+
+        // `tx.poll_ready` reports `NotReady` while the channel is at
+        // capacity, which is exactly the backpressure we want to surface
+        // here rather than treating a full buffer as fatal.
+        match self.tx.poll_ready() {
+            Ok(Async::Ready(())) => {
+                self.ready = true;
+                Ok(Async::Ready(()))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(Error::Closed(self.state.get_error_or_terminated())),
         }
     }
 
+    /// # Panics
+    ///
+    /// Panics if called without a preceding call to `poll_ready` that
+    /// returned `Async::Ready`, per the `Service` contract that `call` may
+    /// assume readiness was already checked.
     fn call(&mut self, request: Request) -> Self::Future {
-        // TODO:
-        // ideally we'd poll_ready again here so we don't allocate the oneshot
-        // if the try_send is about to fail, but sadly we can't call poll_ready
-        // outside of task context.
+        assert!(
+            self.ready,
+            "called Buffer::call before Buffer::poll_ready reported Ready"
+        );
+        self.ready = false;
+
         let (tx, rx) = oneshot::channel();
 
-        let sent = self.tx.try_send(Message { request, tx });
-        if sent.is_err() {
-            self.state.open.store(false, Ordering::Release);
-            ResponseFuture {
-                state: ResponseState::Failed,
-            }
-        } else {
-            ResponseFuture {
+        match self.tx.try_send(Message { request, tx }) {
+            Ok(()) => ResponseFuture {
                 state: ResponseState::Rx(rx),
-            }
+                shared: self.state.clone(),
+            },
+            // We raced another clone of this buffer for the last slot; tell
+            // the caller to try again once `poll_ready` is `Ready`, rather
+            // than tearing the whole buffer down.
+            Err(ref e) if e.is_full() => ResponseFuture {
+                state: ResponseState::Full,
+                shared: self.state.clone(),
+            },
+            Err(_) => ResponseFuture {
+                state: ResponseState::Failed(self.state.get_error_or_terminated()),
+                shared: self.state.clone(),
+            },
         }
     }
 }
@@ -170,6 +262,8 @@ where
         Self {
             tx: self.tx.clone(),
             state: self.state.clone(),
+            // The clone hasn't had `poll_ready` called on it yet.
+            ready: false,
         }
     }
 }
@@ -179,6 +273,7 @@ where
 impl<T, Request> Future for ResponseFuture<T, Request>
 where
     T: DirectService<Request>,
+    T::Error: Send + Sync,
 {
     type Item = T::Response;
     type Error = Error<T::Error>;
@@ -190,13 +285,21 @@ where
             let fut;
 
             match self.state {
-                Failed => {
-                    return Err(Error::Closed);
+                Failed(ref error) => {
+                    return Err(Error::Closed(error.clone()));
+                }
+                Full => {
+                    return Err(Error::Full);
                 }
                 Rx(ref mut rx) => match rx.poll() {
-                    Ok(Async::Ready(f)) => fut = f,
+                    Ok(Async::Ready(Ok(f))) => fut = f,
+                    Ok(Async::Ready(Err(error))) => return Err(Error::Closed(error)),
                     Ok(Async::NotReady) => return Ok(Async::NotReady),
-                    Err(_) => return Err(Error::Closed),
+                    Err(_) => {
+                        // The worker dropped our sender without replying,
+                        // most likely because it terminated unexpectedly.
+                        return Err(Error::Closed(self.shared.get_error_or_terminated()));
+                    }
                 },
                 Poll(ref mut fut) => {
                     return fut.poll().map_err(Error::Inner);
@@ -240,11 +343,31 @@ where
 
         Ok(Async::Ready(None))
     }
+
+    /// Record that the inner service has failed, and let every message
+    /// still queued (the current one, plus anything left in `rx`) know the
+    /// concrete cause rather than leaving its caller hanging.
+    fn failed(&mut self, error: ServiceError<T::Error>)
+    where
+        T::Error: Send + Sync,
+    {
+        let error = Arc::new(error);
+        self.state.set_error(error.clone());
+
+        if let Some(msg) = self.current_message.take() {
+            let _ = msg.tx.send(Err(error.clone()));
+        }
+
+        while let Ok(Async::Ready(Some(msg))) = self.rx.poll() {
+            let _ = msg.tx.send(Err(error.clone()));
+        }
+    }
 }
 
 impl<T, Request> Future for Worker<T, Request>
 where
     T: DirectService<Request>,
+    T::Error: Send + Sync,
 {
     type Item = ();
     type Error = ();
@@ -263,7 +386,7 @@ where
                             //
                             // An error means the request had been canceled in-between
                             // our calls, the response future will just be dropped.
-                            let _ = msg.tx.send(response);
+                            let _ = msg.tx.send(Ok(response));
 
                             // Try to queue another request before we poll outstanding requests.
                             any_outstanding = true;
@@ -276,8 +399,9 @@ where
                             // We want to also make progress on current requests
                             break;
                         }
-                        Err(_) => {
-                            self.state.open.store(false, Ordering::Release);
+                        Err(e) => {
+                            self.current_message = Some(msg);
+                            self.failed(ServiceError::PollReady(e));
                             return Ok(().into());
                         }
                     }
@@ -298,13 +422,28 @@ where
             }
 
             if self.finish {
-                try_ready!(self.service.poll_close().map_err(|_| ()));
-                // We are all done!
-                break;
+                match self.service.poll_close() {
+                    Ok(Async::Ready(())) => {
+                        // We are all done!
+                        break;
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => {
+                        self.failed(ServiceError::PollClose(e));
+                        return Ok(().into());
+                    }
+                }
             } else {
-                if let Async::Ready(()) = self.service.poll_outstanding().map_err(|_| ())? {
-                    // Note to future iterations that there's no reason to call poll_outsanding.
-                    any_outstanding = false;
+                match self.service.poll_outstanding() {
+                    Ok(Async::Ready(())) => {
+                        // Note to future iterations that there's no reason to call poll_outstanding.
+                        any_outstanding = false;
+                    }
+                    Ok(Async::NotReady) => {}
+                    Err(e) => {
+                        self.failed(ServiceError::PollOutstanding(e));
+                        return Ok(().into());
+                    }
                 }
             }
         }
@@ -323,7 +462,8 @@ where
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Inner(ref why) => fmt::Display::fmt(why, f),
-            Error::Closed => f.pad("buffer closed"),
+            Error::Closed(ref why) => write!(f, "buffer closed: {}", why),
+            Error::Full => f.pad("buffer at capacity; await poll_ready before retrying"),
         }
     }
 }
@@ -333,17 +473,57 @@ where
     T: error::Error,
 {
     fn cause(&self) -> Option<&error::Error> {
-        if let Error::Inner(ref why) = *self {
-            Some(why)
-        } else {
-            None
+        match *self {
+            Error::Inner(ref why) => Some(why),
+            Error::Closed(ref why) => Some(why.as_ref()),
+            Error::Full => None,
         }
     }
 
     fn description(&self) -> &str {
         match *self {
             Error::Inner(ref e) => e.description(),
-            Error::Closed => "buffer closed",
+            Error::Closed(_) => "buffer closed",
+            Error::Full => "buffer at capacity",
+        }
+    }
+}
+
+// ===== impl ServiceError =====
+
+impl<T> fmt::Display for ServiceError<T>
+where
+    T: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServiceError::PollReady(ref why) => write!(f, "poll_ready failed: {}", why),
+            ServiceError::PollOutstanding(ref why) => write!(f, "poll_outstanding failed: {}", why),
+            ServiceError::PollClose(ref why) => write!(f, "poll_close failed: {}", why),
+            ServiceError::Terminated => f.pad("worker terminated unexpectedly"),
+        }
+    }
+}
+
+impl<T> error::Error for ServiceError<T>
+where
+    T: error::Error,
+{
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ServiceError::PollReady(ref why) => Some(why),
+            ServiceError::PollOutstanding(ref why) => Some(why),
+            ServiceError::PollClose(ref why) => Some(why),
+            ServiceError::Terminated => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        match *self {
+            ServiceError::PollReady(_) => "poll_ready failed",
+            ServiceError::PollOutstanding(_) => "poll_outstanding failed",
+            ServiceError::PollClose(_) => "poll_close failed",
+            ServiceError::Terminated => "worker terminated unexpectedly",
         }
     }
 }
@@ -370,4 +550,104 @@ where
     fn description(&self) -> &str {
         "error spawning buffer task"
     }
-}
\ No newline at end of file
+}
+
+// ===== impl ServiceBuffer =====
+
+/// Adapts a plain `Service` so it can be driven by the same `Worker` used
+/// for `DirectService`s.
+///
+/// A `Service` drives its returned futures on its own, so there is nothing
+/// for `poll_outstanding`/`poll_close` to do here: they're both no-ops that
+/// report `Ready` immediately.
+struct ServiceAdapter<T>(T);
+
+impl<T, Request> Service<Request> for ServiceAdapter<T>
+where
+    T: Service<Request>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = T::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.0.poll_ready()
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        self.0.call(request)
+    }
+}
+
+impl<T, Request> DirectService<Request> for ServiceAdapter<T>
+where
+    T: Service<Request>,
+{
+    fn poll_outstanding(&mut self) -> Poll<(), Self::Error> {
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_close(&mut self) -> Poll<(), Self::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Adds a buffer in front of an inner service that is an ordinary
+/// `tower_service::Service` rather than a `DirectService`.
+///
+/// Use this when the inner service drives its own futures to completion and
+/// doesn't need the worker to pump `poll_outstanding`/`poll_close` on its
+/// behalf. Prefer `Buffer` if the inner service is a `DirectService`.
+pub struct ServiceBuffer<T, Request>
+where
+    T: Service<Request>,
+{
+    inner: Buffer<ServiceAdapter<T>, Request>,
+}
+
+impl<T, Request> ServiceBuffer<T, Request>
+where
+    T: Service<Request>,
+{
+    /// Creates a new `ServiceBuffer` wrapping `service`.
+    ///
+    /// See `Buffer::new` for the meaning of `bound` and `executor`.
+    pub fn new<E>(service: T, bound: usize, executor: &E) -> Result<Self, SpawnError<T>>
+    where
+        E: Executor<Worker<ServiceAdapter<T>, Request>>,
+        T::Error: Send + Sync,
+    {
+        Buffer::new(ServiceAdapter(service), bound, executor)
+            .map(|inner| ServiceBuffer { inner })
+            .map_err(|e| SpawnError { inner: e.inner.0 })
+    }
+}
+
+impl<T, Request> Service<Request> for ServiceBuffer<T, Request>
+where
+    T: Service<Request>,
+    T::Error: Send + Sync,
+{
+    type Response = T::Response;
+    type Error = Error<T::Error>;
+    type Future = ResponseFuture<ServiceAdapter<T>, Request>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        self.inner.call(request)
+    }
+}
+
+impl<T, Request> Clone for ServiceBuffer<T, Request>
+where
+    T: Service<Request>,
+{
+    fn clone(&self) -> Self {
+        ServiceBuffer {
+            inner: self.inner.clone(),
+        }
+    }
+}